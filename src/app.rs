@@ -3,10 +3,30 @@ use futures_util::{StreamExt, pin_mut};
 use matrix_sdk::{
     AuthSession, Client, ServerName,
     config::SyncSettings,
-    encryption::{BackupDownloadStrategy, EncryptionSettings, VerificationState},
+    encryption::{
+        BackupDownloadStrategy, EncryptionSettings, VerificationState,
+        verification::{Emoji, SasState, SasVerification, Verification, VerificationRequest, VerificationRequestState},
+    },
     matrix_auth::MatrixSession,
-    ruma::events::{
-        key::verification::request::ToDeviceKeyVerificationRequestEvent, room::message::OriginalSyncRoomMessageEvent,
+    media::{MediaFormat, MediaRequestParameters, MediaThumbnailSettings},
+    ruma::{
+        RoomId, uint,
+        api::client::{
+            account::register::v3::{Request as RegistrationRequest, RegistrationKind},
+            error::{ErrorBody as RumaErrorBody, ErrorKind},
+            filter::{FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter},
+            media::Method,
+            session::get_login_types::v3::LoginType,
+            sync::sync_events::v3::Filter as SyncFilter,
+            uiaa::{AuthData, Dummy, UiaaInfo},
+        },
+        events::{
+            key::verification::request::ToDeviceKeyVerificationRequestEvent,
+            room::{
+                MediaSource,
+                message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+            },
+        },
     },
 };
 use matrix_sdk_ui::{
@@ -14,13 +34,14 @@ use matrix_sdk_ui::{
     eyeball_im::VectorDiff,
     room_list_service::{self, RoomList, filters::new_filter_non_left},
     sync_service::{self, SyncService},
+    timeline::{RoomExt, TimelineItem},
 };
 use serde::{Deserialize, Serialize};
 use std::{
     path::{Path, PathBuf},
     sync::Arc,
 };
-use tokio::{fs, sync::mpsc};
+use tokio::{fs, sync::mpsc, sync::Mutex};
 use tracing::{info, instrument, warn};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,6 +51,33 @@ pub struct Config {
     db_path: PathBuf,
     session_path: PathBuf,
     homeserver_url: String,
+    /// When true, `App::start` registers a new account instead of logging into an existing one.
+    #[serde(default)]
+    register: bool,
+    #[serde(default)]
+    login_method: LoginMethod,
+    /// Lazy-load room members during sync instead of pulling full membership state up front.
+    #[serde(default = "default_lazy_load_members")]
+    lazy_load_members: bool,
+    /// When lazy-loading, also include memberships the client should already know about.
+    #[serde(default)]
+    include_redundant_members: bool,
+    /// If set, room timelines only sync these event types (e.g. `["m.room.message"]`); room
+    /// state is not filtered by type.
+    #[serde(default)]
+    sync_event_types: Option<Vec<String>>,
+}
+
+fn default_lazy_load_members() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginMethod {
+    #[default]
+    Password,
+    Sso,
 }
 
 #[derive(Clone)]
@@ -37,6 +85,8 @@ pub struct App {
     config: Config,
     client: Client,
     tx: mpsc::Sender<Event>,
+    /// The SAS verification currently awaiting user confirmation, if any.
+    sas: Arc<Mutex<Option<SasVerification>>>,
 }
 
 #[derive(Debug)]
@@ -47,6 +97,18 @@ enum Event {
     SyncServiceState(sync_service::State),
     RoomDiff(Vec<VectorDiff<room_list_service::Room>>),
     FatalMatrixErr(matrix_sdk::Error),
+    /// A UIAA stage came back that we don't know how to complete automatically.
+    UiaaStageRequired(String),
+    /// Emoji SAS data to show the user; confirm with `confirm_verification`. Decimal SAS data
+    /// is not included.
+    SasVerification(Vec<Emoji>),
+    Timeline(Vec<VectorDiff<Arc<TimelineItem>>>),
+    /// The SSO redirect URL to open in a browser to complete login.
+    SsoLoginUrl(String),
+    /// The sync loop hit a soft logout; a re-authentication attempt is underway.
+    SoftLogout,
+    MediaReady { source: MediaSource, bytes: Vec<u8> },
+    RoomKeysImported { imported_count: usize, total_count: usize },
 }
 
 impl App {
@@ -66,7 +128,12 @@ impl App {
             .await
             .context("build client")?;
 
-        let app = App { config, client, tx };
+        let app = App {
+            config,
+            client,
+            tx,
+            sas: Arc::new(Mutex::new(None)),
+        };
         app.auth().await.context("auth")?;
         app.register_event_handlers();
         tokio::spawn(app.clone().verification_listener());
@@ -82,9 +149,151 @@ impl App {
         info!("Controller task starting");
         while let Some(ev) = rx.recv().await {
             info!("Event: {ev:?}");
+            if let Event::VerificationRequest(ev) = ev {
+                tokio::spawn(self.clone().handle_verification_request(ev));
+            }
         }
     }
 
+    /// Accept an incoming key-verification request and drive it through SAS to completion.
+    async fn handle_verification_request(self, ev: ToDeviceKeyVerificationRequestEvent) {
+        if let Err(err) = self.drive_verification_request(&ev).await {
+            warn!("verification request from {} failed: {err}", ev.sender);
+        }
+    }
+
+    async fn drive_verification_request(&self, ev: &ToDeviceKeyVerificationRequestEvent) -> Result<()> {
+        let request = self
+            .client
+            .encryption()
+            .get_verification_request(&ev.sender, &ev.content.transaction_id)
+            .await
+            .context("verification request not found")?;
+        request.accept().await.context("accept verification request")?;
+
+        // Subscribing to `changes()` only yields *future* states, so a `Ready` (or a SAS the
+        // peer already started) that fired before we subscribed would otherwise be missed.
+        if self
+            .handle_verification_request_state(&request, request.state())
+            .await?
+        {
+            return Ok(());
+        }
+        let mut changes = request.changes();
+        while let Some(state) = changes.next().await {
+            if self.handle_verification_request_state(&request, state).await? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle one `VerificationRequestState`, starting or adopting SAS verification as needed.
+    /// Returns `Ok(true)` once the request has handed off to SAS, `Ok(false)` for states that
+    /// need no action, and an error if the request was cancelled or SAS could not be started.
+    async fn handle_verification_request_state(
+        &self,
+        request: &VerificationRequest,
+        state: VerificationRequestState,
+    ) -> Result<bool> {
+        match state {
+            VerificationRequestState::Ready { .. } => {
+                let Some(sas) = request.start_sas().await.context("start sas")? else {
+                    bail!("peer does not support sas verification");
+                };
+                *self.sas.lock().await = Some(sas.clone());
+                tokio::spawn(self.clone().drive_sas(sas));
+                Ok(true)
+            }
+            // The peer started SAS before we called `start_sas()` ourselves; adopt it instead
+            // of waiting for a `Ready` that will never come.
+            VerificationRequestState::Transitioned { verification } => {
+                let Verification::SasV1(sas) = verification else {
+                    bail!("peer started an unsupported verification method");
+                };
+                sas.accept().await.context("accept sas verification")?;
+                *self.sas.lock().await = Some(sas.clone());
+                tokio::spawn(self.clone().drive_sas(sas));
+                Ok(true)
+            }
+            VerificationRequestState::Cancelled(info) => {
+                bail!("verification request cancelled: {info:?}");
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn drive_sas(self, sas: SasVerification) {
+        let mut changes = sas.changes();
+        while let Some(state) = changes.next().await {
+            match state {
+                SasState::KeysExchanged { emojis, .. } => {
+                    let Some(emojis) = emojis else {
+                        warn!("homeserver did not provide emoji sas data");
+                        continue;
+                    };
+                    let ev = Event::SasVerification(emojis.emojis.to_vec());
+                    if self.tx.send(ev).await.is_err() {
+                        break;
+                    }
+                }
+                SasState::Done { .. } => {
+                    info!("SAS verification complete");
+                    *self.sas.lock().await = None;
+                    break;
+                }
+                SasState::Cancelled(info) => {
+                    warn!("SAS verification cancelled: {info:?}");
+                    *self.sas.lock().await = None;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Export all known Megolm room keys to an encrypted file, protected by `passphrase`, in
+    /// the standard `m.megolm` export format. Lets a user move sessions between installs or
+    /// recover history that `BackupDownloadStrategy::AfterDecryptionFailure` couldn't fetch.
+    pub async fn export_room_keys(&self, path: PathBuf, passphrase: &str) -> Result<()> {
+        info!("Exporting room keys to {}", path.display());
+        self.client
+            .encryption()
+            .export_room_keys(path, passphrase, |_| true)
+            .await
+            .context("export room keys")?;
+        Ok(())
+    }
+
+    /// Import Megolm room keys from an encrypted export file produced by `export_room_keys`.
+    pub async fn import_room_keys(&self, path: PathBuf, passphrase: &str) -> Result<()> {
+        info!("Importing room keys from {}", path.display());
+        let result = self
+            .client
+            .encryption()
+            .import_room_keys(path, passphrase)
+            .await
+            .context("import room keys")?;
+        let ev = Event::RoomKeysImported {
+            imported_count: result.imported_count,
+            total_count: result.total_count,
+        };
+        let _ = self.tx.send(ev).await;
+        Ok(())
+    }
+
+    /// Confirm that the emojis shown to the user matched the other device's.
+    pub async fn confirm_verification(&self) -> Result<()> {
+        let sas = self.sas.lock().await.clone().context("no active sas verification")?;
+        sas.confirm().await.context("confirm sas verification")
+    }
+
+    /// Abort the in-progress SAS verification, e.g. because the emojis didn't match.
+    pub async fn cancel_verification(&self) -> Result<()> {
+        let sas = self.sas.lock().await.clone().context("no active sas verification")?;
+        sas.cancel().await.context("cancel sas verification")
+    }
+
     fn register_event_handlers(&self) {
         info!("Registering event handlers");
         macro_rules! event {
@@ -101,13 +310,77 @@ impl App {
                     });
             }};
         }
-        event!(OriginalSyncRoomMessageEvent, Event::SyncRoom);
         event!(ToDeviceKeyVerificationRequestEvent, Event::VerificationRequest);
+
+        let app = self.clone();
+        self.client
+            .add_event_handler(move |ev: OriginalSyncRoomMessageEvent, _: Client| {
+                let app = app.clone();
+                async move {
+                    app.prefetch_message_thumbnail(&ev).await;
+                    if let Err(err) = app.tx.send(Event::SyncRoom(ev)).await {
+                        warn!("could not send event to control thread: {err}");
+                    }
+                }
+            });
+    }
+
+    /// Eagerly warm the media cache with a thumbnail for image/video/file messages.
+    async fn prefetch_message_thumbnail(&self, ev: &OriginalSyncRoomMessageEvent) {
+        let source = match &ev.content.msgtype {
+            MessageType::Image(c) => c.source.clone(),
+            MessageType::Video(c) => c.source.clone(),
+            MessageType::File(c) => c.source.clone(),
+            _ => return,
+        };
+        let format = MediaFormat::Thumbnail(MediaThumbnailSettings {
+            width: uint!(320),
+            height: uint!(320),
+            method: Method::Scale,
+            animated: false,
+        });
+        if let Err(err) = self.fetch_media(source, format).await {
+            warn!("failed to prefetch thumbnail: {err}");
+        }
+    }
+
+    /// Fetch media (full file or a scaled/cropped thumbnail), served from the SDK's media
+    /// cache when possible, and surface it as `Event::MediaReady`.
+    pub async fn fetch_media(&self, source: MediaSource, format: MediaFormat) -> Result<Vec<u8>> {
+        let request = MediaRequestParameters { source: source.clone(), format };
+        let bytes = self
+            .client
+            .media()
+            .get_media_content(&request, true)
+            .await
+            .context("fetch media content")?;
+        let ev = Event::MediaReady { source, bytes: bytes.clone() };
+        let _ = self.tx.send(ev).await;
+        Ok(bytes)
+    }
+
+    /// Build the sync filter from `Config`: lazy-loads room members (optionally with
+    /// redundant memberships included) and, if configured, restricts room timeline events to
+    /// an allowlist of types. Shrinks the initial `sync_once` payload on large accounts.
+    fn sync_filter(&self) -> FilterDefinition {
+        let lazy_load_options = if self.config.lazy_load_members {
+            LazyLoadOptions::Enabled { include_redundant_members: self.config.include_redundant_members }
+        } else {
+            LazyLoadOptions::Disabled
+        };
+        let state_filter = RoomEventFilter { lazy_load_options: lazy_load_options.clone(), ..Default::default() };
+        let timeline_filter = RoomEventFilter {
+            lazy_load_options,
+            types: self.config.sync_event_types.clone(),
+            ..Default::default()
+        };
+        let room_filter = RoomFilter { state: state_filter, timeline: timeline_filter, ..Default::default() };
+        FilterDefinition { room: room_filter, ..Default::default() }
     }
 
     async fn setup_sync(&self) -> Result<()> {
         info!("Setting up sync");
-        let settings = SyncSettings::default();
+        let settings = SyncSettings::default().filter(SyncFilter::FilterDefinition(self.sync_filter()));
         let sync_service = SyncService::builder(self.client.clone())
             .build()
             .await
@@ -127,15 +400,59 @@ impl App {
             .sync_once(settings.clone())
             .await
             .context("first client sync")?;
-        let sync = self.clone();
-        tokio::spawn(async move {
-            if let Err(err) = sync.client.sync(settings).await {
-                let _ = sync.tx.send(Event::FatalMatrixErr(err)).await;
-            }
-        });
+        tokio::spawn(self.clone().sync_forever(settings));
         Ok(())
     }
 
+    /// Runs the long-lived sync loop, recovering from a soft logout instead of dying.
+    async fn sync_forever(self, settings: SyncSettings) {
+        loop {
+            let Err(err) = self.client.sync(settings.clone()).await else {
+                return;
+            };
+            if !Self::is_soft_logout(&err) {
+                let _ = self.tx.send(Event::FatalMatrixErr(err)).await;
+                return;
+            }
+            warn!("Soft logout detected, attempting to re-authenticate");
+            let _ = self.tx.send(Event::SoftLogout).await;
+            if let Err(err) = self.recover_soft_logout().await {
+                warn!("Failed to recover from soft logout: {err}");
+                return;
+            }
+            info!("Recovered from soft logout, resuming sync");
+        }
+    }
+
+    fn is_soft_logout(err: &matrix_sdk::Error) -> bool {
+        matches!(
+            err.as_client_api_error().map(|e| &e.body),
+            Some(RumaErrorBody::Standard {
+                kind: ErrorKind::UnknownToken { soft_logout: true },
+                ..
+            })
+        )
+    }
+
+    /// Re-authenticate after a soft logout, preferring a refresh token if the session has one,
+    /// falling back to a fresh login with the configured `login_method`.
+    async fn recover_soft_logout(&self) -> Result<()> {
+        if let Some(AuthSession::Matrix(session)) = self.client.session() {
+            if session.tokens.refresh_token.is_some() {
+                self.client
+                    .matrix_auth()
+                    .refresh_access_token()
+                    .await
+                    .context("refresh access token")?;
+                return self.persist_session().await.context("persist refreshed session");
+            }
+        }
+        match self.config.login_method {
+            LoginMethod::Password => self.login().await,
+            LoginMethod::Sso => self.login_sso().await,
+        }
+    }
+
     async fn auth(&self) -> Result<()> {
         info!("Initializing auth");
         match self.restore_session().await {
@@ -146,7 +463,101 @@ impl App {
             Ok(false) => info!("No session was found"),
             Err(err) => warn!("Session restore failed: {err}. Falling back to login"),
         };
-        self.login().await.context("login")
+        if self.config.register {
+            return self.register().await.context("register");
+        }
+        match self.config.login_method {
+            LoginMethod::Password => self.login().await.context("login"),
+            LoginMethod::Sso => self.login_sso().await.context("sso login"),
+        }
+    }
+
+    async fn login_sso(&self) -> Result<()> {
+        info!("Attempting sso login");
+        let login_types = self
+            .client
+            .matrix_auth()
+            .get_login_types()
+            .await
+            .context("get login types")?;
+        if !login_types.flows.iter().any(|flow| matches!(flow, LoginType::Sso(_))) {
+            bail!("homeserver does not advertise sso login");
+        }
+        let tx = self.tx.clone();
+        self.client
+            .matrix_auth()
+            .login_sso(|url| {
+                let tx = tx.clone();
+                async move {
+                    let _ = tx.send(Event::SsoLoginUrl(url)).await;
+                    Ok(())
+                }
+            })
+            .initial_device_display_name("collin-matrix-client")
+            .await
+            .context("sso login")?;
+        self.persist_session().await.context("persist session after sso login")
+    }
+
+    async fn register(&self) -> Result<()> {
+        info!("Attempting registration");
+        let mut request = RegistrationRequest::new();
+        request.username = Some(self.config.username.clone());
+        request.password = Some(self.config.password.clone());
+        request.initial_device_display_name = Some("collin-matrix-client".to_owned());
+        request.kind = RegistrationKind::User;
+
+        let resp = match self.client.matrix_auth().register(request.clone()).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                let Some(uiaa_info) = err.as_uiaa_response().cloned() else {
+                    bail!("registration failed without a recoverable uiaa response: {err}");
+                };
+                self.complete_registration_uiaa(request, uiaa_info)
+                    .await
+                    .context("complete uiaa registration")?
+            }
+        };
+        info!("Registration resp: {resp:#?}");
+        self.persist_session().await.context("persist session after registration")
+    }
+
+    /// Walks a UIAA flow returned by `register`, resubmitting the request with each stage's
+    /// auth data until the homeserver accepts it and returns a real registration response.
+    async fn complete_registration_uiaa(
+        &self,
+        mut request: RegistrationRequest,
+        mut uiaa_info: UiaaInfo,
+    ) -> Result<matrix_sdk::ruma::api::client::account::register::v3::Response> {
+        loop {
+            let session = uiaa_info.session.clone().context("uiaa response missing session id")?;
+            let stage = uiaa_info
+                .flows
+                .first()
+                .context("uiaa response has no auth flows")?
+                .stages
+                .iter()
+                .find(|stage| !uiaa_info.completed.contains(stage))
+                .context("uiaa response has no remaining auth flow stages")?
+                .clone();
+            request.auth = Some(match stage.as_str() {
+                "m.login.dummy" => AuthData::Dummy(Dummy::new(session)),
+                "m.login.terms" => {
+                    AuthData::new("m.login.terms", Some(session)).context("build terms auth data")?
+                }
+                other => {
+                    let _ = self.tx.send(Event::UiaaStageRequired(other.to_owned())).await;
+                    bail!("unsupported uiaa stage: {other}");
+                }
+            });
+            match self.client.matrix_auth().register(request.clone()).await {
+                Ok(resp) => return Ok(resp),
+                Err(err) => match err.as_uiaa_response().cloned() {
+                    Some(next) => uiaa_info = next,
+                    None => bail!("registration failed: {err}"),
+                },
+            }
+        }
     }
 
     async fn login(&self) -> Result<()> {
@@ -159,10 +570,13 @@ impl App {
             .await
             .context("matrix auth")?;
         info!("Login resp: {resp:#?}");
-        let session = self
-            .client
-            .session()
-            .context("no session after login")?;
+        self.persist_session().await.context("persist session after login")
+    }
+
+    /// Serialize the client's current `MatrixSession` to `session_path` so it can be restored
+    /// on the next run, the same way after login, registration, SSO, or soft-logout recovery.
+    async fn persist_session(&self) -> Result<()> {
+        let session = self.client.session().context("no session to persist")?;
         match session {
             AuthSession::Matrix(session) => {
                 let s = serde_yaml::to_string(&session).context("serialize session")?;
@@ -172,7 +586,7 @@ impl App {
             }
             _ => bail!("unknown session typ: {session:?}"),
         }
-        todo!()
+        Ok(())
     }
 
     async fn restore_session(&self) -> Result<bool> {
@@ -191,6 +605,67 @@ impl App {
         Ok(true)
     }
 
+    /// Send a plain-text message to a room.
+    pub async fn send_text(&self, room_id: &RoomId, body: impl Into<String>) -> Result<()> {
+        self.room(room_id)?
+            .send(RoomMessageEventContent::text_plain(body))
+            .await
+            .context("send text message")?;
+        Ok(())
+    }
+
+    /// Send a message to a room, rendering the body as markdown.
+    pub async fn send_markdown(&self, room_id: &RoomId, body: impl Into<String>) -> Result<()> {
+        self.room(room_id)?
+            .send(RoomMessageEventContent::text_markdown(body))
+            .await
+            .context("send markdown message")?;
+        Ok(())
+    }
+
+    /// Send an `m.emote` message (e.g. "/me waves") to a room.
+    pub async fn send_emote(&self, room_id: &RoomId, body: impl Into<String>) -> Result<()> {
+        self.room(room_id)?
+            .send(RoomMessageEventContent::emote_plain(body))
+            .await
+            .context("send emote message")?;
+        Ok(())
+    }
+
+    /// Build a `matrix-sdk-ui` timeline for a room and stream its diffs as `Event::Timeline`.
+    pub async fn timeline(&self, room_id: &RoomId) -> Result<()> {
+        let timeline = Arc::new(
+            self.room(room_id)?
+                .timeline()
+                .await
+                .context("build timeline")?,
+        );
+        tokio::spawn(self.clone().timeline_listener(timeline));
+        Ok(())
+    }
+
+    fn room(&self, room_id: &RoomId) -> Result<matrix_sdk::Room> {
+        self.client
+            .get_room(room_id)
+            .with_context(|| format!("room not found: {room_id}"))
+    }
+
+    async fn timeline_listener(self, timeline: Arc<matrix_sdk_ui::Timeline>) {
+        info!("Starting timeline listener");
+        let (items, stream) = timeline.subscribe_batched().await;
+        let reset = Event::Timeline(vec![VectorDiff::Reset { values: items }]);
+        if self.tx.send(reset).await.is_err() {
+            return;
+        }
+        pin_mut!(stream);
+        while let Some(diffs) = stream.next().await {
+            let ev = Event::Timeline(diffs);
+            if self.tx.send(ev).await.is_err() {
+                break;
+            }
+        }
+    }
+
     async fn room_list_listener(self, rooms: RoomList) {
         info!("Starting room list listener");
         let (stream, controller) = rooms.entries_with_dynamic_adapters(5);